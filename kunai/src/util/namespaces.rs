@@ -1,17 +1,23 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::io::Error as IoError;
 use std::num::ParseIntError;
 
 use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
 
 use std::path::PathBuf;
 use std::process;
-use std::{fs::File, os::fd::AsRawFd};
+use std::process::{Child, Command, Stdio};
+use std::{
+    fs::File,
+    os::fd::{AsRawFd, FromRawFd},
+};
 
 use kunai_macros::StrEnum;
 use libc::{c_int, pid_t, syscall, SYS_pidfd_open, CLONE_NEWNS};
 use thiserror::Error;
 
-#[allow(dead_code)]
 pub fn pidfd_open(pid: pid_t, flags: c_int) -> Result<RawFd, IoError> {
     let result = unsafe { syscall(SYS_pidfd_open, pid, flags) };
 
@@ -72,6 +78,22 @@ impl Kind {
     pub fn path(&self, pid: u32) -> PathBuf {
         PathBuf::from(format!("/proc/{pid}/ns")).join(self.as_str())
     }
+
+    /// Returns the `CLONE_NEW*` flag associated to this namespace `Kind`, suitable
+    /// for a combined `setns(2)` call (several flags OR-ed together) or `unshare(2)`.
+    #[inline]
+    pub fn clone_flag(&self) -> c_int {
+        match self {
+            Kind::Cgroup => libc::CLONE_NEWCGROUP,
+            Kind::Ipc => libc::CLONE_NEWIPC,
+            Kind::Mnt => CLONE_NEWNS,
+            Kind::Net => libc::CLONE_NEWNET,
+            Kind::Pid => libc::CLONE_NEWPID,
+            Kind::Time => libc::CLONE_NEWTIME,
+            Kind::User => libc::CLONE_NEWUSER,
+            Kind::Uts => libc::CLONE_NEWUTS,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -186,6 +208,12 @@ pub enum Error {
     Enter(Namespace, IoError),
     #[error("setns exit error namespace={0}: {1}")]
     Exit(Namespace, IoError),
+    #[error("pidfd_open error pid={0}: {1}")]
+    PidFd(pid_t, IoError),
+    #[error("combined setns enter error pid={0}: {1}")]
+    EnterMulti(pid_t, IoError),
+    #[error("namespace kind {0} is not supported by NsCommand")]
+    Unsupported(Kind),
     #[error("{0}")]
     Namespace(#[from] NsError),
     #[error("{0}")]
@@ -261,6 +289,540 @@ impl Switcher {
     }
 }
 
+/// `setns` only ever mutates the namespace membership of the calling OS
+/// thread, so calling [Switcher::do_in_namespace] from a thread a tokio or
+/// rayon runtime may reuse for unrelated work is unsafe: other tasks later
+/// scheduled onto that same thread would silently run inside the switched-to
+/// namespace until `exit()` returns. `ScopedSwitcher` confines the whole
+/// `enter -> f() -> exit` sequence to a freshly spawned OS thread that no
+/// other task can observe, and joins it before returning `f`'s result.
+#[derive(Debug)]
+pub struct ScopedSwitcher {
+    switcher: Switcher,
+}
+
+impl ScopedSwitcher {
+    pub fn new(kind: Kind, pid: u32) -> Result<Self, Error> {
+        Switcher::new(kind, pid).map(|switcher| Self { switcher })
+    }
+
+    /// Runs `f` to completion on a dedicated OS thread, after switching that
+    /// thread into the target namespace, then joins the thread before
+    /// returning. A panic in `f` is propagated to the caller rather than
+    /// silently swallowed.
+    pub fn do_in_namespace<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error> + Send,
+        T: Send,
+    {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| self.switcher.do_in_namespace(f))
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+    }
+}
+
+#[derive(Debug)]
+struct MultiNsEntry {
+    target: Namespace,
+    src: File,
+}
+
+/// Joins several namespaces of a target `pid` at once through a single
+/// `setns(2)` call on a pidfd, as opposed to [Switcher] which opens and
+/// joins namespaces one `/proc/<pid>/ns/<kind>` file (and one `setns` call)
+/// at a time. Requires a kernel new enough to support `pidfd_open(2)` (5.3+)
+/// and multi-namespace `setns(2)` (5.8+).
+#[derive(Debug)]
+pub struct MultiSwitcher {
+    pid: pid_t,
+    pidfd: File,
+    entries: Vec<MultiNsEntry>,
+    flags: c_int,
+}
+
+impl MultiSwitcher {
+    pub fn new<I>(pid: u32, kinds: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = Kind>,
+    {
+        let self_pid = process::id();
+        let raw_pidfd = pidfd_open(pid as pid_t, 0).map_err(|e| Error::PidFd(pid as pid_t, e))?;
+        // safe because pidfd_open returned a valid, newly-opened fd we now own
+        let pidfd = unsafe { File::from_raw_fd(raw_pidfd) };
+
+        let mut entries = vec![];
+        let mut flags = 0;
+
+        for kind in kinds {
+            let self_ns = Namespace::from_pid(kind, self_pid)?;
+            let target_ns = Namespace::from_pid(kind, pid)?;
+
+            // nothing to do, we are already in the same namespace as target
+            if self_ns == target_ns {
+                continue;
+            }
+
+            entries.push(MultiNsEntry {
+                target: target_ns,
+                src: Namespace::open(kind, self_pid)?,
+            });
+
+            flags |= kind.clone_flag();
+        }
+
+        Ok(Self {
+            pid: pid as pid_t,
+            pidfd,
+            entries,
+            flags,
+        })
+    }
+
+    /// Run function `f` after switching into the namespaces. If switching into/from
+    /// the namespaces fails the appropriate error is returned [Error::EnterMulti] or
+    /// [Error::Exit]. If any namespace error is met it returns immediately, otherwise
+    /// the result of `f` is returned.
+    #[inline(always)]
+    pub fn do_in_namespace<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        self.enter()?;
+        let res = f();
+        self.exit()?;
+        res
+    }
+
+    #[inline]
+    fn enter(&self) -> Result<(), Error> {
+        // nothing to join, every selected kind already matches our own namespace
+        if self.flags == 0 {
+            return Ok(());
+        }
+
+        setns(self.pidfd.as_raw_fd(), self.flags).map_err(|e| Error::EnterMulti(self.pid, e))
+    }
+
+    #[inline]
+    fn exit(&self) -> Result<(), Error> {
+        // nstype=0 so that the kernel infers the kind from each saved source fd
+        for entry in self.entries.iter().rev() {
+            setns(entry.src.as_raw_fd(), 0).map_err(|e| Error::Exit(entry.target, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Order in which namespaces must be entered for `setns(2)` to succeed: the
+/// user namespace comes first as it changes the caller's capabilities, and
+/// the mount namespace comes last so it is entered while those capabilities
+/// still hold (see namespaces(7), "Use of the setns() system call").
+const ENTER_ORDER: [Kind; 8] = [
+    Kind::User,
+    Kind::Pid,
+    Kind::Time,
+    Kind::Cgroup,
+    Kind::Ipc,
+    Kind::Uts,
+    Kind::Net,
+    Kind::Mnt,
+];
+
+#[derive(Debug)]
+struct OrderedNsEntry {
+    target: Namespace,
+    src: File,
+    dst: File,
+}
+
+/// Joins several namespaces of a target `pid`, one `setns(2)` call per [Kind],
+/// always in the canonical [ENTER_ORDER] regardless of the order `kinds` were
+/// given in. `exit()` unwinds in the reverse order, restoring each changed
+/// namespace from the fd saved at construction time.
+#[derive(Debug)]
+pub struct OrderedSwitcher {
+    entries: Vec<OrderedNsEntry>,
+}
+
+impl OrderedSwitcher {
+    pub fn new<I>(pid: u32, kinds: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = Kind>,
+    {
+        let self_pid = process::id();
+        let requested: HashSet<Kind> = kinds.into_iter().collect();
+        let mut entries = vec![];
+
+        for &kind in ENTER_ORDER.iter().filter(|k| requested.contains(k)) {
+            let self_ns = Namespace::from_pid(kind, self_pid)?;
+            let target_ns = Namespace::from_pid(kind, pid)?;
+
+            // nothing to do, we are already in the same namespace as target
+            if self_ns == target_ns {
+                continue;
+            }
+
+            entries.push(OrderedNsEntry {
+                target: target_ns,
+                src: Namespace::open(kind, self_pid)?,
+                dst: Namespace::open(kind, pid)?,
+            });
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Run function `f` after switching into the namespaces. If switching into/from
+    /// a namespace fails the appropriate error is returned [Error::Enter] or
+    /// [Error::Exit], tagged with the [Namespace] that failed. If any namespace
+    /// error is met it returns immediately, otherwise the result of `f` is returned.
+    #[inline(always)]
+    pub fn do_in_namespace<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        self.enter()?;
+        let res = f();
+        self.exit()?;
+        res
+    }
+
+    #[inline]
+    fn enter(&self) -> Result<(), Error> {
+        for entry in self.entries.iter() {
+            setns(entry.dst.as_raw_fd(), 0).map_err(|e| Error::Enter(entry.target, e))?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn exit(&self) -> Result<(), Error> {
+        for entry in self.entries.iter().rev() {
+            setns(entry.src.as_raw_fd(), 0).map_err(|e| Error::Exit(entry.target, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Plan built by [NsCommand::spawn] to join the target namespaces from the
+/// forked child, preferring a single combined `setns(2)` call on a pidfd and
+/// falling back to one `setns(2)` call per [Kind] when `pidfd_open(2)` is
+/// unavailable (e.g. on kernels older than 5.3).
+enum NsJoinPlan {
+    PidFd { pidfd: File, flags: c_int },
+    PerKind(Vec<(Kind, File)>),
+}
+
+fn build_join_plan(pid: u32, kinds: &[Kind]) -> Result<NsJoinPlan, Error> {
+    let self_pid = process::id();
+    let mut changed = vec![];
+
+    for &kind in ENTER_ORDER.iter().filter(|k| kinds.contains(k)) {
+        let self_ns = Namespace::from_pid(kind, self_pid)?;
+        let target_ns = Namespace::from_pid(kind, pid)?;
+
+        if self_ns != target_ns {
+            changed.push(kind);
+        }
+    }
+
+    match pidfd_open(pid as pid_t, 0) {
+        Ok(raw_pidfd) => {
+            // safe because pidfd_open returned a valid, newly-opened fd we now own
+            let pidfd = unsafe { File::from_raw_fd(raw_pidfd) };
+            let flags = changed.iter().fold(0, |acc, k| acc | k.clone_flag());
+            Ok(NsJoinPlan::PidFd { pidfd, flags })
+        }
+        Err(_) => {
+            let mut entries = vec![];
+            for kind in changed {
+                entries.push((kind, Namespace::open(kind, pid)?));
+            }
+            Ok(NsJoinPlan::PerKind(entries))
+        }
+    }
+}
+
+/// `nsenter`-style process launcher: wraps [Command] so the spawned child
+/// joins a target `pid`'s namespaces before `execvp`-ing the configured
+/// program.
+///
+/// Namespace errors encountered in the child are reported back to the
+/// parent through the pipe `Command`'s `pre_exec` hook already uses for
+/// exec-preparation failures, so [NsCommand::spawn] fails cleanly instead of
+/// exec'ing the program in the wrong namespaces.
+///
+/// `Kind::Pid` is **not** supported: `Command::spawn` only forks once, and
+/// `setns(CLONE_NEWPID)` never moves the caller itself into the new PID
+/// namespace, only processes it *subsequently* forks. Since our `pre_exec`
+/// runs in that one forked child right before `execvp` replaces its image,
+/// the exec'd program would keep running in the original PID namespace while
+/// silently joining the other requested namespaces - exactly the kind of
+/// wrong-namespace exec this type exists to prevent. Correctly joining a
+/// target PID namespace needs a second fork, after `setns` and before exec,
+/// with the grandchild carrying the `Child` handle; until that is
+/// implemented, [NsCommand::spawn] rejects `Kind::Pid` with
+/// [Error::Unsupported].
+pub struct NsCommand {
+    command: Command,
+    pid: u32,
+    kinds: Vec<Kind>,
+}
+
+impl NsCommand {
+    pub fn new<S: AsRef<OsStr>>(program: S, pid: u32) -> Self {
+        Self {
+            command: Command::new(program),
+            pid,
+            kinds: vec![],
+        }
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.command.arg(arg);
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.command.args(args);
+        self
+    }
+
+    pub fn stdin(&mut self, cfg: Stdio) -> &mut Self {
+        self.command.stdin(cfg);
+        self
+    }
+
+    pub fn stdout(&mut self, cfg: Stdio) -> &mut Self {
+        self.command.stdout(cfg);
+        self
+    }
+
+    pub fn stderr(&mut self, cfg: Stdio) -> &mut Self {
+        self.command.stderr(cfg);
+        self
+    }
+
+    /// Adds a namespace `Kind` the child must join. Kinds are always joined
+    /// in the safe [ENTER_ORDER], regardless of the order they were added in.
+    /// `Kind::Pid` is accepted here but rejected by [NsCommand::spawn]; see
+    /// the type-level docs.
+    pub fn kind(&mut self, kind: Kind) -> &mut Self {
+        self.kinds.push(kind);
+        self
+    }
+
+    pub fn kinds<I: IntoIterator<Item = Kind>>(&mut self, kinds: I) -> &mut Self {
+        self.kinds.extend(kinds);
+        self
+    }
+
+    pub fn spawn(&mut self) -> Result<Child, Error> {
+        if self.kinds.contains(&Kind::Pid) {
+            return Err(Error::Unsupported(Kind::Pid));
+        }
+
+        let plan = build_join_plan(self.pid, &self.kinds)?;
+
+        // safe because the closure only calls setns on fds we own and returns
+        // an io::Error on failure instead of silently exec'ing the program
+        unsafe {
+            self.command.pre_exec(move || match &plan {
+                NsJoinPlan::PidFd { pidfd, flags } => {
+                    if *flags != 0 {
+                        setns(pidfd.as_raw_fd(), *flags)?;
+                    }
+                    Ok(())
+                }
+                NsJoinPlan::PerKind(entries) => {
+                    for (kind, file) in entries {
+                        setns(file.as_raw_fd(), kind.clone_flag())?;
+                    }
+                    Ok(())
+                }
+            });
+        }
+
+        self.command.spawn().map_err(Error::other)
+    }
+}
+
+const ALL_KINDS: [Kind; 8] = [
+    Kind::Cgroup,
+    Kind::Ipc,
+    Kind::Mnt,
+    Kind::Net,
+    Kind::Pid,
+    Kind::Time,
+    Kind::User,
+    Kind::Uts,
+];
+
+/// Reads the process start time (field 22 of `/proc/<pid>/stat`, in clock
+/// ticks since boot) used by [NamespaceRegistry] to detect pid reuse: two
+/// processes with the same pid but different start times are not the same
+/// process.
+fn start_time(pid: u32) -> Result<u64, NsError> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"))?;
+    // the command name (2nd field) is parenthesized and may itself contain
+    // spaces or parens, so locate the fields that follow its closing paren
+    let after = stat.rsplit_once(')').ok_or(NsError::Format)?.1;
+    after
+        .split_whitespace()
+        .nth(19)
+        .ok_or(NsError::Format)?
+        .parse::<u64>()
+        .map_err(NsError::from)
+}
+
+#[derive(Debug, Default)]
+struct PidEntry {
+    start_time: u64,
+    namespaces: HashMap<Kind, Namespace>,
+}
+
+/// System-wide index correlating PIDs sharing the same namespaces, built by
+/// scanning `/proc/*/ns/*`. This lets callers group processes by container
+/// (or any other namespace boundary) without re-reading `/proc` links for
+/// every event. The scan is racy by nature - processes can appear or
+/// disappear between the `readdir` and the subsequent `readlink`s - so any
+/// `ENOENT`/`ESRCH` encountered for a given pid is treated as "this process
+/// is gone" rather than propagated as an error.
+#[derive(Debug, Default)]
+pub struct NamespaceRegistry {
+    pids: HashMap<u32, PidEntry>,
+    index: HashMap<Namespace, HashSet<u32>>,
+}
+
+impl NamespaceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rescans `/proc/*/ns/*`, only re-reading the namespaces of pids whose
+    /// start time changed (new pid, or old pid reused by a new process)
+    /// since the last call, so it is cheap enough to call periodically.
+    pub fn refresh(&mut self) -> Result<(), NsError> {
+        let mut seen = HashSet::new();
+
+        for entry in std::fs::read_dir("/proc")? {
+            let entry = entry?;
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok())
+            else {
+                continue;
+            };
+
+            let start_time = match start_time(pid) {
+                Ok(st) => st,
+                Err(_) => continue, // process gone, or not readable: skip it
+            };
+
+            seen.insert(pid);
+
+            // identity (pid, start_time) unchanged since last scan: nothing to redo
+            if self.pids.get(&pid).map(|e| e.start_time) == Some(start_time) {
+                continue;
+            }
+
+            // new pid, or pid reused by a different process: drop stale entries first
+            self.forget(pid);
+
+            let mut namespaces = HashMap::new();
+            for kind in ALL_KINDS {
+                // any error here (ENOENT/ESRCH because the process is gone, or an
+                // unreadable link) just means this namespace kind is skipped
+                if let Ok(ns) = Namespace::from_pid(kind, pid) {
+                    self.index.entry(ns).or_default().insert(pid);
+                    namespaces.insert(kind, ns);
+                }
+            }
+
+            self.pids.insert(
+                pid,
+                PidEntry {
+                    start_time,
+                    namespaces,
+                },
+            );
+        }
+
+        let gone: Vec<u32> = self
+            .pids
+            .keys()
+            .filter(|pid| !seen.contains(pid))
+            .copied()
+            .collect();
+
+        for pid in gone {
+            self.forget(pid);
+        }
+
+        Ok(())
+    }
+
+    fn forget(&mut self, pid: u32) {
+        let Some(entry) = self.pids.remove(&pid) else {
+            return;
+        };
+
+        for ns in entry.namespaces.values() {
+            if let Some(pids) = self.index.get_mut(ns) {
+                pids.remove(&pid);
+                if pids.is_empty() {
+                    self.index.remove(ns);
+                }
+            }
+        }
+    }
+
+    /// PIDs known to share the given `namespace`.
+    pub fn pids_in(&self, namespace: Namespace) -> Vec<u32> {
+        self.index
+            .get(&namespace)
+            .map(|pids| pids.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Namespaces `pid` was last seen in, by [Kind].
+    pub fn namespaces_of(&self, pid: u32) -> HashMap<Kind, Namespace> {
+        self.pids
+            .get(&pid)
+            .map(|e| e.namespaces.clone())
+            .unwrap_or_default()
+    }
+
+    /// Lowest PID sharing every namespace known for `pid`, a good
+    /// container-leader heuristic. Falls back to `pid` itself if `pid` is not
+    /// known to the registry.
+    pub fn peer_of(&self, pid: u32) -> u32 {
+        let Some(entry) = self.pids.get(&pid) else {
+            return pid;
+        };
+
+        self.pids
+            .keys()
+            .copied()
+            .filter(|p| {
+                entry
+                    .namespaces
+                    .values()
+                    .all(|ns| self.index.get(ns).map_or(false, |pids| pids.contains(p)))
+            })
+            .min()
+            .unwrap_or(pid)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -282,6 +844,14 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_scoped_switcher() {
+        let pid = process::id();
+        let switcher = ScopedSwitcher::new(Kind::Mnt, pid).unwrap();
+        let res = switcher.do_in_namespace(|| Ok(42)).unwrap();
+        assert_eq!(res, 42);
+    }
+
     #[test]
     fn test_read() {
         let pid = process::id();
@@ -302,6 +872,80 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_multi_switcher() {
+        let pid = process::id();
+        MultiSwitcher::new(
+            pid,
+            [
+                Kind::Cgroup,
+                Kind::Ipc,
+                Kind::Mnt,
+                Kind::Net,
+                Kind::Pid,
+                Kind::Time,
+                Kind::User,
+                Kind::Uts,
+            ],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_ordered_switcher() {
+        let pid = process::id();
+        OrderedSwitcher::new(
+            pid,
+            [
+                Kind::Cgroup,
+                Kind::Ipc,
+                Kind::Mnt,
+                Kind::Net,
+                Kind::Pid,
+                Kind::Time,
+                Kind::User,
+                Kind::Uts,
+            ],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_ns_command() {
+        let pid = process::id();
+        let status = NsCommand::new("true", pid)
+            .kinds([Kind::Mnt, Kind::Net, Kind::Uts])
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_ns_command_rejects_pid() {
+        let pid = process::id();
+        let err = NsCommand::new("true", pid)
+            .kind(Kind::Pid)
+            .spawn()
+            .unwrap_err();
+        assert!(matches!(err, Error::Unsupported(Kind::Pid)));
+    }
+
+    #[test]
+    fn test_namespace_registry() {
+        let pid = process::id();
+        let mut reg = NamespaceRegistry::new();
+        reg.refresh().unwrap();
+
+        let namespaces = reg.namespaces_of(pid);
+        assert_eq!(namespaces.len(), ALL_KINDS.len());
+
+        let mnt = namespaces[&Kind::Mnt];
+        assert!(reg.pids_in(mnt).contains(&pid));
+        assert!(reg.peer_of(pid) <= pid);
+    }
+
     #[test]
     fn test_eq() {
         let pid = process::id();